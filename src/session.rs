@@ -1,32 +1,55 @@
 //! Sessions and session management utilities
 
 use bincode::{self, Infinite};
-use chrono::{DateTime, UTC};
+use chrono::{DateTime, Duration, UTC};
 use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
 use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
 use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::sha2::Sha256;
 use ring::rand::SystemRandom;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use std::collections::HashMap;
+use std::marker::PhantomData;
 use typemap;
 
 use error::SessionError;
 
 const SCRYPT_SALT: &'static [u8; 31] = b"rust-secure-session-scrypt-salt";
 
+/// Distinct from `SCRYPT_SALT` so that `SigningSessionManager::from_password` derives a MAC key
+/// that is provably independent of the AEAD key the AEAD managers would derive from the same
+/// password: scrypt's output is fully determined by `(password, salt, params)`, so reusing
+/// `SCRYPT_SALT` here would make the two keys byte-identical.
+const SIGNING_SCRYPT_SALT: &'static [u8; 39] = b"rust-secure-session-scrypt-salt-signing";
+
 
 /// Container for serializing and deserializing the session when sending it to and receiving it
 /// from a client.
+///
+/// `V` is the type of the payload stored in the `Session`. It defaults to the untyped
+/// `HashMap<String, Vec<u8>>` byte map so existing callers are unaffected; applications that want
+/// to store a strongly-typed struct can use `SessionTransport<MyStruct>` instead.
 #[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
-pub struct SessionTransport {
+pub struct SessionTransport<V = HashMap<String, Vec<u8>>> {
     /// Optional UTC timestamp for when the session expires.
     pub expires: Option<DateTime<UTC>>,
     /// The session that was deserialized or will be serialized.
-    pub session: Session,
+    pub session: Session<V>,
 }
 
 
 /// Persistent session passed to client as a cookie.
 ///
+/// `Session` is generic over the payload type `V`, which defaults to the untyped
+/// `HashMap<String, Vec<u8>>` byte map used by the original API. Applications that want to store
+/// a strongly-typed, `Serialize + DeserializeOwned` struct directly (instead of hand-serializing
+/// it into bytes) can use `Session<MyStruct>` via `Session::from_value`/`Session::get`.
+///
 /// ```
 /// use secure_session::session::Session;
 ///
@@ -38,14 +61,78 @@ pub struct SessionTransport {
 /// assert_eq!(session.get_bytes("foo"), None);
 /// ```
 #[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
-pub struct Session {
-    bytes: HashMap<String, Vec<u8>>,
+pub struct Session<V = HashMap<String, Vec<u8>>> {
+    value: V,
+}
+
+impl<V> Session<V> {
+    /// Wrap an arbitrary, typed value as a session payload.
+    ///
+    /// ```
+    /// use secure_session::session::Session;
+    ///
+    /// let session = Session::from_value(42u32);
+    /// assert_eq!(session.get(), &42u32);
+    /// ```
+    pub fn from_value(value: V) -> Self {
+        Session { value: value }
+    }
+
+    /// Borrow the session's payload.
+    ///
+    /// ```
+    /// use secure_session::session::Session;
+    ///
+    /// let session = Session::from_value(42u32);
+    /// assert_eq!(session.get(), &42u32);
+    /// ```
+    pub fn get(&self) -> &V {
+        &self.value
+    }
+
+    /// Mutably borrow the session's payload.
+    ///
+    /// ```
+    /// use secure_session::session::Session;
+    ///
+    /// let mut session = Session::from_value(42u32);
+    /// *session.get_mut() += 1;
+    /// assert_eq!(session.get(), &43u32);
+    /// ```
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.value
+    }
+
+    /// Replace the session's payload, returning the previous value.
+    ///
+    /// ```
+    /// use secure_session::session::Session;
+    ///
+    /// let mut session = Session::from_value(42u32);
+    /// assert_eq!(session.set(7u32), 42u32);
+    /// assert_eq!(session.get(), &7u32);
+    /// ```
+    pub fn set(&mut self, value: V) -> V {
+        ::std::mem::replace(&mut self.value, value)
+    }
+
+    /// Unwrap the session, returning its payload.
+    ///
+    /// ```
+    /// use secure_session::session::Session;
+    ///
+    /// let session = Session::from_value(42u32);
+    /// assert_eq!(session.into_value(), 42u32);
+    /// ```
+    pub fn into_value(self) -> V {
+        self.value
+    }
 }
 
-impl Session {
+impl Session<HashMap<String, Vec<u8>>> {
     /// Create an empty session.
     pub fn new() -> Self {
-        Session { bytes: HashMap::new() }
+        Session { value: HashMap::new() }
     }
 
     /// Store bytes for the given key.
@@ -58,7 +145,7 @@ impl Session {
     /// assert_eq!(session.get_bytes("foo"), Some(&b"bar".to_vec()));
     /// ```
     pub fn get_bytes(&self, key: &str) -> Option<&Vec<u8>> {
-        self.bytes.get(key)
+        self.value.get(key)
     }
 
     /// Retrieve bytes for the given key.
@@ -71,7 +158,7 @@ impl Session {
     /// assert_eq!(session.insert_bytes("foo", b"bar".to_vec()), None);
     /// ```
     pub fn insert_bytes(&mut self, key: &str, bytes: Vec<u8>) -> Option<Vec<u8>> {
-        self.bytes.insert(key.to_string(), bytes)
+        self.value.insert(key.to_string(), bytes)
     }
 
     /// Remove bytes stored at the given key.
@@ -86,7 +173,7 @@ impl Session {
     /// assert_eq!(session.remove_bytes("foo"), Some(b"bar".to_vec()));
     /// ```
     pub fn remove_bytes(&mut self, key: &str) -> Option<Vec<u8>> {
-        self.bytes.remove(key)
+        self.value.remove(key)
     }
 
     /// Check whether the session contains bytes stored at the given key.
@@ -99,7 +186,7 @@ impl Session {
     /// assert!(session.contains_key("foo"));
     /// ```
     pub fn contains_key(&self, key: &str) -> bool {
-        self.bytes.contains_key(key)
+        self.value.contains_key(key)
     }
 
     /// Clears all the values from the session.
@@ -117,16 +204,20 @@ impl Session {
     /// assert!(!session.contains_key("wat"));
     /// ```
     pub fn clear(&mut self) {
-        self.bytes.clear()
+        self.value.clear()
     }
 }
 
-impl typemap::Key for Session {
-    type Value = Session;
+impl<V: 'static> typemap::Key for Session<V> {
+    type Value = Session<V>;
 }
 
 /// Base trait that provides session management.
-pub trait SessionManager: Send + Sync {
+///
+/// `V` is the type of the payload carried by the managed `SessionTransport`/`Session`. It
+/// defaults to the untyped `HashMap<String, Vec<u8>>` byte map so implementors and callers that
+/// don't need a typed payload are unaffected.
+pub trait SessionManager<V = HashMap<String, Vec<u8>>>: Send + Sync {
     /// Using `scrypt` with params `n=12`, `r=8`, `p=1`, generate the key material used for the
     /// underlying crypto functions.
     ///
@@ -139,35 +230,87 @@ pub trait SessionManager: Send + Sync {
     ///   * Decrypt (optional)
     ///   * Verify signature / MAC
     ///   * Parse / deserialize into a `SessionTransport` struct
-    fn deserialize(&self, bytes: &[u8]) -> Result<SessionTransport, SessionError>;
+    fn deserialize(&self, bytes: &[u8]) -> Result<SessionTransport<V>, SessionError>;
 
     /// Given a session perform the following options to convert a `SessionTransport ` into bytes:
     ///
     ///   * Encrypt (optional)
     ///   * Sign / MAC
     ///   * Encode / serialize into bytes
-    fn serialize(&self, session: &SessionTransport) -> Result<Vec<u8>, SessionError>;
+    fn serialize(&self, session: &SessionTransport<V>) -> Result<Vec<u8>, SessionError>;
 
     /// Whether or not the sessions are encrypted.
     fn is_encrypted(&self) -> bool;
+
+    /// This manager's configured time-to-live for new and renewed sessions, if any. When set,
+    /// `serialize` stamps `SessionTransport.expires` as `UTC::now() + ttl` and `deserialize`
+    /// rejects transports whose `expires` is in the past.
+    fn ttl(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Produce a fresh transport carrying the same session data as `session` but a new expiry,
+    /// per this manager's `ttl`.
+    ///
+    /// Bytes serialized from the old transport are not invalidated: `deserialize` keeps accepting
+    /// them until their own `expires` has passed (indefinitely if it is `None`). Rotating a
+    /// server-side session id is done with `store::SessionStore::renew`.
+    fn renew(&self, session: &SessionTransport<V>) -> SessionTransport<V>
+        where V: Clone
+    {
+        SessionTransport {
+            expires: self.ttl().map(|ttl| UTC::now() + ttl),
+            session: session.session.clone(),
+        }
+    }
+}
+
+/// Whether `expires` names an instant that has already passed.
+pub(crate) fn expired(expires: Option<DateTime<UTC>>) -> bool {
+    expires.map(|expires| expires < UTC::now()).unwrap_or(false)
+}
+
+/// If `ttl` is set, return a copy of `session` with `expires` stamped to `UTC::now() + ttl`;
+/// otherwise return `session` unchanged.
+fn stamp_expiry<V: Clone>(session: &SessionTransport<V>, ttl: Option<Duration>) -> SessionTransport<V> {
+    match ttl {
+        Some(ttl) => {
+            SessionTransport {
+                expires: Some(UTC::now() + ttl),
+                session: session.session.clone(),
+            }
+        }
+        None => session.clone(),
+    }
 }
 
 
 /// Uses the ChaCha20Poly1305 AEAD to provide signed, encrypted sessions.
-pub struct ChaCha20Poly1305SessionManager {
+pub struct ChaCha20Poly1305SessionManager<V = HashMap<String, Vec<u8>>> {
     rng: SystemRandom,
     aead_key: [u8; 32],
+    ttl: Option<Duration>,
+    value_type: PhantomData<V>,
 }
 
-impl ChaCha20Poly1305SessionManager {
+impl<V> ChaCha20Poly1305SessionManager<V> {
     /// Using a saved key, generate a `ChaCha20Poly1305SessionManager`.
     pub fn from_key(aead_key: [u8; 32]) -> Self {
         ChaCha20Poly1305SessionManager {
             rng: SystemRandom::new(),
             aead_key: aead_key,
+            ttl: None,
+            value_type: PhantomData,
         }
     }
 
+    /// Set the time-to-live stamped onto sessions produced by `serialize`/`renew`. Sessions
+    /// older than their stamped expiry are rejected by `deserialize`.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
     fn random_bytes(&self, buf: &mut [u8]) -> Result<(), SessionError> {
         self.rng
             .fill(buf)
@@ -182,7 +325,9 @@ impl ChaCha20Poly1305SessionManager {
     }
 }
 
-impl SessionManager for ChaCha20Poly1305SessionManager {
+impl<V> SessionManager<V> for ChaCha20Poly1305SessionManager<V>
+    where V: Clone + Serialize + DeserializeOwned + Send + Sync
+{
     fn from_password(password: &[u8]) -> Self {
         let params = if cfg!(test) {
             // scrypt is *slow*, so use these params for testing
@@ -199,7 +344,7 @@ impl SessionManager for ChaCha20Poly1305SessionManager {
         ChaCha20Poly1305SessionManager::from_key(aead_key)
     }
 
-    fn deserialize(&self, bytes: &[u8]) -> Result<SessionTransport, SessionError> {
+    fn deserialize(&self, bytes: &[u8]) -> Result<SessionTransport<V>, SessionError> {
         if bytes.len() <= 40 {
             return Err(SessionError::ValidationError);
         }
@@ -225,10 +370,19 @@ impl SessionManager for ChaCha20Poly1305SessionManager {
             return Err(SessionError::ValidationError);
         }
 
-        Ok(bincode::deserialize(&plaintext[16..plaintext.len()]).unwrap()) // TODO unwrap
+        let transport: SessionTransport<V> = bincode::deserialize(&plaintext[16..plaintext.len()])
+            .unwrap(); // TODO unwrap
+        if expired(transport.expires) {
+            info!("Session expired");
+            return Err(SessionError::ValidationError);
+        }
+
+        Ok(transport)
     }
 
-    fn serialize(&self, session: &SessionTransport) -> Result<Vec<u8>, SessionError> {
+    fn serialize(&self, session: &SessionTransport<V>) -> Result<Vec<u8>, SessionError> {
+        let session = stamp_expiry(session, self.ttl);
+
         let mut nonce = [0; 8];
         self.random_bytes(&mut nonce)?;
 
@@ -271,12 +425,540 @@ impl SessionManager for ChaCha20Poly1305SessionManager {
     /// ```
     /// use secure_session::session::{ChaCha20Poly1305SessionManager, SessionManager};
     ///
-    /// let manager = ChaCha20Poly1305SessionManager::from_key(*b"01234567012345670123456701234567");
+    /// let manager: ChaCha20Poly1305SessionManager =
+    ///     ChaCha20Poly1305SessionManager::from_key(*b"01234567012345670123456701234567");
+    /// assert!(manager.is_encrypted());
+    /// ```
+    fn is_encrypted(&self) -> bool {
+        true
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+}
+
+
+/// Uses the AES-256-GCM AEAD to provide signed, encrypted sessions.
+///
+/// Unlike `ChaCha20Poly1305SessionManager`, this manager uses a 12-byte nonce (plus the same
+/// 16-byte tag) rather than an 8-byte one: `rust-crypto`'s `AesGcm` hard-asserts a 12-byte nonce,
+/// the size mandated by standard AES-GCM, so the wire header is 4 bytes wider than the
+/// ChaCha20Poly1305 framing. Hosts with AES-NI will see significantly faster seal/open than the
+/// software ChaCha20 implementation.
+pub struct AesGcmSessionManager<V = HashMap<String, Vec<u8>>> {
+    rng: SystemRandom,
+    aead_key: [u8; 32],
+    ttl: Option<Duration>,
+    value_type: PhantomData<V>,
+}
+
+impl<V> AesGcmSessionManager<V> {
+    /// Using a saved key, generate an `AesGcmSessionManager`.
+    pub fn from_key(aead_key: [u8; 32]) -> Self {
+        AesGcmSessionManager {
+            rng: SystemRandom::new(),
+            aead_key: aead_key,
+            ttl: None,
+            value_type: PhantomData,
+        }
+    }
+
+    /// Set the time-to-live stamped onto sessions produced by `serialize`/`renew`. Sessions
+    /// older than their stamped expiry are rejected by `deserialize`.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    fn random_bytes(&self, buf: &mut [u8]) -> Result<(), SessionError> {
+        self.rng
+            .fill(buf)
+            .map_err(|_| {
+                warn!("Failed to get random bytes");
+                SessionError::InternalError
+            })
+    }
+
+    fn aead(&self, nonce: &[u8; 12]) -> AesGcm<'static> {
+        AesGcm::new(KeySize::KeySize256, &self.aead_key, nonce, &[])
+    }
+}
+
+impl<V> SessionManager<V> for AesGcmSessionManager<V>
+    where V: Clone + Serialize + DeserializeOwned + Send + Sync
+{
+    fn from_password(password: &[u8]) -> Self {
+        let params = if cfg!(test) {
+            // scrypt is *slow*, so use these params for testing
+            ScryptParams::new(1, 8, 1)
+        } else {
+            ScryptParams::new(12, 8, 1)
+        };
+
+        let mut aead_key = [0; 32];
+        info!("Generating key material. This may take some time.");
+        scrypt(password, SCRYPT_SALT, &params, &mut aead_key);
+        info!("Key material generated.");
+
+        AesGcmSessionManager::from_key(aead_key)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<SessionTransport<V>, SessionError> {
+        if bytes.len() <= 44 {
+            return Err(SessionError::ValidationError);
+        }
+
+        let mut ciphertext = vec![0; bytes.len() - 28];
+        let mut plaintext = vec![0; bytes.len() - 28];
+        let mut tag = [0; 16];
+        let mut nonce = [0; 12];
+
+        for i in 0..12 {
+            nonce[i] = bytes[i];
+        }
+        for i in 0..16 {
+            tag[i] = bytes[i + 12];
+        }
+        for i in 0..(bytes.len() - 28) {
+            ciphertext[i] = bytes[i + 28];
+        }
+
+        let mut aead = self.aead(&nonce);
+        if !aead.decrypt(&ciphertext, &mut plaintext, &tag) {
+            info!("Failed to decrypt session");
+            return Err(SessionError::ValidationError);
+        }
+
+        let transport: SessionTransport<V> = bincode::deserialize(&plaintext[16..plaintext.len()])
+            .unwrap(); // TODO unwrap
+        if expired(transport.expires) {
+            info!("Session expired");
+            return Err(SessionError::ValidationError);
+        }
+
+        Ok(transport)
+    }
+
+    fn serialize(&self, session: &SessionTransport<V>) -> Result<Vec<u8>, SessionError> {
+        let session = stamp_expiry(session, self.ttl);
+
+        let mut nonce = [0; 12];
+        self.random_bytes(&mut nonce)?;
+
+        let session_bytes = bincode::serialize(&session, Infinite).unwrap(); // TODO unwrap
+        let mut padding = [0; 16];
+        self.random_bytes(&mut padding)?;
+
+        let mut plaintext = vec![0; session_bytes.len() + 16];
+
+        for i in 0..16 {
+            plaintext[i] = padding[i];
+        }
+        for i in 0..session_bytes.len() {
+            plaintext[i + 16] = session_bytes[i];
+        }
+
+        let mut ciphertext = vec![0; plaintext.len()];
+        let mut tag = [0; 16];
+        let mut aead = self.aead(&nonce);
+
+        aead.encrypt(&plaintext, &mut ciphertext, &mut tag);
+
+        let mut transport = vec![0; ciphertext.len() + 28];
+
+        for i in 0..12 {
+            transport[i] = nonce[i];
+        }
+        for i in 0..16 {
+            transport[i + 12] = tag[i];
+        }
+        for i in 0..ciphertext.len() {
+            transport[i + 28] = ciphertext[i];
+        }
+
+        Ok(transport)
+    }
+
+    /// Whether or not the sessions are encrypted.
+    ///
+    /// ```
+    /// use secure_session::session::{AesGcmSessionManager, SessionManager};
+    ///
+    /// let manager: AesGcmSessionManager =
+    ///     AesGcmSessionManager::from_key(*b"01234567012345670123456701234567");
     /// assert!(manager.is_encrypted());
     /// ```
     fn is_encrypted(&self) -> bool {
         true
     }
+
+    fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+}
+
+/// Uses HMAC-SHA256 to provide signed, tamper-evident, but *unencrypted* sessions.
+///
+/// Many applications want tamper-proof-but-readable cookies (e.g. a public user id plus a CSRF
+/// token) and shouldn't have to pay for full AEAD encryption to get that guarantee.
+/// `SigningSessionManager` serializes the `SessionTransport` in the clear and appends an
+/// HMAC-SHA256 tag computed over the plaintext, rejecting the session on `deserialize` if the
+/// tag doesn't match.
+pub struct SigningSessionManager<V = HashMap<String, Vec<u8>>> {
+    mac_key: [u8; 32],
+    ttl: Option<Duration>,
+    value_type: PhantomData<V>,
+}
+
+impl<V> SigningSessionManager<V> {
+    /// Using a saved key, generate a `SigningSessionManager`.
+    pub fn from_key(mac_key: [u8; 32]) -> Self {
+        SigningSessionManager {
+            mac_key: mac_key,
+            ttl: None,
+            value_type: PhantomData,
+        }
+    }
+
+    /// Set the time-to-live stamped onto sessions produced by `serialize`/`renew`. Sessions
+    /// older than their stamped expiry are rejected by `deserialize`.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::new(Sha256::new(), &self.mac_key)
+    }
+}
+
+impl<V> SessionManager<V> for SigningSessionManager<V>
+    where V: Clone + Serialize + DeserializeOwned + Send + Sync
+{
+    fn from_password(password: &[u8]) -> Self {
+        let params = if cfg!(test) {
+            // scrypt is *slow*, so use these params for testing
+            ScryptParams::new(1, 8, 1)
+        } else {
+            ScryptParams::new(12, 8, 1)
+        };
+
+        // Derive a MAC key dedicated to this manager (as opposed to an AEAD key) via the same
+        // scrypt key-derivation path used by the AEAD managers, but with a manager-specific salt
+        // so the two keys are independent even for the same password.
+        let mut mac_key = [0; 32];
+        info!("Generating key material. This may take some time.");
+        scrypt(password, SIGNING_SCRYPT_SALT, &params, &mut mac_key);
+        info!("Key material generated.");
+
+        SigningSessionManager::from_key(mac_key)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<SessionTransport<V>, SessionError> {
+        if bytes.len() <= 32 {
+            return Err(SessionError::ValidationError);
+        }
+
+        let body_len = bytes.len() - 32;
+        let body = &bytes[..body_len];
+        let tag = &bytes[body_len..];
+
+        let mut mac = self.mac();
+        mac.input(body);
+        if mac.result() != MacResult::new(tag) {
+            info!("Failed to verify session signature");
+            return Err(SessionError::ValidationError);
+        }
+
+        let transport: SessionTransport<V> = bincode::deserialize(body).unwrap(); // TODO unwrap
+        if expired(transport.expires) {
+            info!("Session expired");
+            return Err(SessionError::ValidationError);
+        }
+
+        Ok(transport)
+    }
+
+    fn serialize(&self, session: &SessionTransport<V>) -> Result<Vec<u8>, SessionError> {
+        let session = stamp_expiry(session, self.ttl);
+        let mut transport = bincode::serialize(&session, Infinite).unwrap(); // TODO unwrap
+
+        let mut mac = self.mac();
+        mac.input(&transport);
+        transport.extend_from_slice(mac.result().code());
+
+        Ok(transport)
+    }
+
+    /// Whether or not the sessions are encrypted.
+    ///
+    /// ```
+    /// use secure_session::session::{SigningSessionManager, SessionManager};
+    ///
+    /// let manager: SigningSessionManager =
+    ///     SigningSessionManager::from_key(*b"01234567012345670123456701234567");
+    /// assert!(!manager.is_encrypted());
+    /// ```
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+}
+
+
+/// Wire format version for `XChaCha20Poly1305SessionManager`. Prepended to every serialized
+/// session so that a deployment migrating away from the 8-byte-nonce `ChaCha20Poly1305` framing
+/// cannot mistake one format for the other.
+const XCHACHA20POLY1305_VERSION: u8 = 2;
+
+/// HChaCha20 constants, the same "expand 32-byte k" constants used by ChaCha20.
+const HCHACHA20_CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24)
+}
+
+fn write_u32_le(buf: &mut [u8], value: u32) {
+    buf[0] = value as u8;
+    buf[1] = (value >> 8) as u8;
+    buf[2] = (value >> 16) as u8;
+    buf[3] = (value >> 24) as u8;
+}
+
+fn chacha20_quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] ^= state[a];
+    state[d] = state[d].rotate_left(16);
+
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] ^= state[c];
+    state[b] = state[b].rotate_left(12);
+
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] ^= state[a];
+    state[d] = state[d].rotate_left(8);
+
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] ^= state[c];
+    state[b] = state[b].rotate_left(7);
+}
+
+/// HChaCha20 subkey derivation: given the 32-byte key and the first 16 bytes of an extended
+/// 24-byte nonce, derive a fresh 32-byte subkey. This is the building block that lets
+/// `XChaCha20Poly1305SessionManager` use a full 24-byte random nonce on top of the 8-byte-nonce
+/// ChaCha20Poly1305 this crate already has.
+fn hchacha20(key: &[u8; 32], nonce16: &[u8; 16]) -> [u8; 32] {
+    let mut state = [0u32; 16];
+
+    state[0..4].copy_from_slice(&HCHACHA20_CONSTANTS);
+    for i in 0..8 {
+        state[4 + i] = read_u32_le(&key[i * 4..i * 4 + 4]);
+    }
+    for i in 0..4 {
+        state[12 + i] = read_u32_le(&nonce16[i * 4..i * 4 + 4]);
+    }
+
+    for _ in 0..10 {
+        chacha20_quarter_round(&mut state, 0, 4, 8, 12);
+        chacha20_quarter_round(&mut state, 1, 5, 9, 13);
+        chacha20_quarter_round(&mut state, 2, 6, 10, 14);
+        chacha20_quarter_round(&mut state, 3, 7, 11, 15);
+
+        chacha20_quarter_round(&mut state, 0, 5, 10, 15);
+        chacha20_quarter_round(&mut state, 1, 6, 11, 12);
+        chacha20_quarter_round(&mut state, 2, 7, 8, 13);
+        chacha20_quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut subkey = [0u8; 32];
+    for i in 0..4 {
+        write_u32_le(&mut subkey[i * 4..i * 4 + 4], state[i]);
+    }
+    for i in 0..4 {
+        write_u32_le(&mut subkey[16 + i * 4..16 + i * 4 + 4], state[12 + i]);
+    }
+
+    subkey
+}
+
+/// Uses XChaCha20-Poly1305 (the extended-nonce construction built on top of this crate's
+/// ChaCha20Poly1305) to provide signed, encrypted sessions with a 24-byte random nonce.
+///
+/// `ChaCha20Poly1305SessionManager` draws an 8-byte random nonce per `serialize`; under a fixed
+/// key, birthday-bound nonce collisions become realistic after a few billion cookies, and a
+/// repeated nonce catastrophically breaks AEAD confidentiality and authenticity.
+/// `XChaCha20Poly1305SessionManager` instead derives a fresh per-message subkey via HChaCha20
+/// from the first 16 bytes of a 24-byte random nonce, then uses the remaining 8 bytes as the
+/// inner ChaCha20Poly1305 nonce. This makes random nonces safe at high traffic volumes without
+/// per-key counter state. The wire framing grows from 8+16+ciphertext to 24+16+ciphertext, with
+/// a leading format version byte so old and new cookies can be told apart.
+pub struct XChaCha20Poly1305SessionManager<V = HashMap<String, Vec<u8>>> {
+    rng: SystemRandom,
+    aead_key: [u8; 32],
+    ttl: Option<Duration>,
+    value_type: PhantomData<V>,
+}
+
+impl<V> XChaCha20Poly1305SessionManager<V> {
+    /// Using a saved key, generate an `XChaCha20Poly1305SessionManager`.
+    pub fn from_key(aead_key: [u8; 32]) -> Self {
+        XChaCha20Poly1305SessionManager {
+            rng: SystemRandom::new(),
+            aead_key: aead_key,
+            ttl: None,
+            value_type: PhantomData,
+        }
+    }
+
+    /// Set the time-to-live stamped onto sessions produced by `serialize`/`renew`. Sessions
+    /// older than their stamped expiry are rejected by `deserialize`.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    fn random_bytes(&self, buf: &mut [u8]) -> Result<(), SessionError> {
+        self.rng
+            .fill(buf)
+            .map_err(|_| {
+                warn!("Failed to get random bytes");
+                SessionError::InternalError
+            })
+    }
+
+    fn aead(&self, nonce: &[u8; 24]) -> ChaCha20Poly1305 {
+        let mut nonce16 = [0; 16];
+        nonce16.copy_from_slice(&nonce[0..16]);
+        let subkey = hchacha20(&self.aead_key, &nonce16);
+
+        let mut inner_nonce = [0; 8];
+        inner_nonce.copy_from_slice(&nonce[16..24]);
+
+        ChaCha20Poly1305::new(&subkey, &inner_nonce, &[])
+    }
+}
+
+impl<V> SessionManager<V> for XChaCha20Poly1305SessionManager<V>
+    where V: Clone + Serialize + DeserializeOwned + Send + Sync
+{
+    fn from_password(password: &[u8]) -> Self {
+        let params = if cfg!(test) {
+            // scrypt is *slow*, so use these params for testing
+            ScryptParams::new(1, 8, 1)
+        } else {
+            ScryptParams::new(12, 8, 1)
+        };
+
+        let mut aead_key = [0; 32];
+        info!("Generating key material. This may take some time.");
+        scrypt(password, SCRYPT_SALT, &params, &mut aead_key);
+        info!("Key material generated.");
+
+        XChaCha20Poly1305SessionManager::from_key(aead_key)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<SessionTransport<V>, SessionError> {
+        if bytes.len() <= 57 {
+            return Err(SessionError::ValidationError);
+        }
+
+        if bytes[0] != XCHACHA20POLY1305_VERSION {
+            info!("Unrecognized session format version");
+            return Err(SessionError::ValidationError);
+        }
+
+        let mut ciphertext = vec![0; bytes.len() - 41];
+        let mut plaintext = vec![0; bytes.len() - 41];
+        let mut nonce = [0; 24];
+        let mut tag = [0; 16];
+
+        for i in 0..24 {
+            nonce[i] = bytes[i + 1];
+        }
+        for i in 0..16 {
+            tag[i] = bytes[i + 25];
+        }
+        for i in 0..(bytes.len() - 41) {
+            ciphertext[i] = bytes[i + 41];
+        }
+
+        let mut aead = self.aead(&nonce);
+        if !aead.decrypt(&ciphertext, &mut plaintext, &tag) {
+            info!("Failed to decrypt session");
+            return Err(SessionError::ValidationError);
+        }
+
+        let transport: SessionTransport<V> = bincode::deserialize(&plaintext[16..plaintext.len()])
+            .unwrap(); // TODO unwrap
+        if expired(transport.expires) {
+            info!("Session expired");
+            return Err(SessionError::ValidationError);
+        }
+
+        Ok(transport)
+    }
+
+    fn serialize(&self, session: &SessionTransport<V>) -> Result<Vec<u8>, SessionError> {
+        let session = stamp_expiry(session, self.ttl);
+
+        let mut nonce = [0; 24];
+        self.random_bytes(&mut nonce)?;
+
+        let session_bytes = bincode::serialize(&session, Infinite).unwrap(); // TODO unwrap
+        let mut padding = [0; 16];
+        self.random_bytes(&mut padding)?;
+
+        let mut plaintext = vec![0; session_bytes.len() + 16];
+
+        for i in 0..16 {
+            plaintext[i] = padding[i];
+        }
+        for i in 0..session_bytes.len() {
+            plaintext[i + 16] = session_bytes[i];
+        }
+
+        let mut ciphertext = vec![0; plaintext.len()];
+        let mut tag = [0; 16];
+        let mut aead = self.aead(&nonce);
+
+        aead.encrypt(&plaintext, &mut ciphertext, &mut tag);
+
+        let mut transport = vec![0; ciphertext.len() + 41];
+
+        transport[0] = XCHACHA20POLY1305_VERSION;
+        for i in 0..24 {
+            transport[i + 1] = nonce[i];
+        }
+        for i in 0..16 {
+            transport[i + 25] = tag[i];
+        }
+        for i in 0..ciphertext.len() {
+            transport[i + 41] = ciphertext[i];
+        }
+
+        Ok(transport)
+    }
+
+    /// Whether or not the sessions are encrypted.
+    ///
+    /// ```
+    /// use secure_session::session::{XChaCha20Poly1305SessionManager, SessionManager};
+    ///
+    /// let manager: XChaCha20Poly1305SessionManager =
+    ///     XChaCha20Poly1305SessionManager::from_key(*b"01234567012345670123456701234567");
+    /// assert!(manager.is_encrypted());
+    /// ```
+    fn is_encrypted(&self) -> bool {
+        true
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
 }
 
 #[cfg(test)]
@@ -285,6 +967,8 @@ mod tests {
     macro_rules! test_cases {
         ($strct: ident, $md: ident) => {
             mod $md  {
+                use chrono::Duration;
+
                 use $crate::session::{$strct, SessionManager, SessionTransport, Session};
 
                 const KEY: [u8; 32] = *b"01234567012345670123456701234567";
@@ -304,9 +988,92 @@ mod tests {
                     assert_eq!(parsed_transport, transport);
                     assert_eq!(parsed_transport.session.get_bytes(&key), Some(&value));
                 }
+
+                #[test]
+                fn with_ttl_stamps_expiry_on_serialize() {
+                    let manager = $strct::from_key(KEY).with_ttl(Duration::days(1));
+                    let transport = SessionTransport { expires: None, session: Session::new() };
+
+                    let bytes = manager.serialize(&transport).expect("couldn't serialize");
+                    let parsed_transport = manager.deserialize(&bytes).expect("couldn't deserialize");
+                    assert!(parsed_transport.expires.is_some());
+                }
+
+                #[test]
+                fn deserialize_rejects_expired_transport() {
+                    use chrono::UTC;
+
+                    let manager = $strct::from_key(KEY);
+                    let transport = SessionTransport {
+                        expires: Some(UTC::now() - Duration::seconds(1)),
+                        session: Session::new(),
+                    };
+
+                    let bytes = manager.serialize(&transport).expect("couldn't serialize");
+                    assert!(manager.deserialize(&bytes).is_err());
+                }
+
+                #[test]
+                fn renew_preserves_session_and_refreshes_expiry() {
+                    use chrono::UTC;
+
+                    let manager = $strct::from_key(KEY).with_ttl(Duration::days(1));
+                    let mut session = Session::new();
+                    let key = "lol".to_string();
+                    let value = b"wat".to_vec();
+                    assert!(session.insert_bytes(&key, value.clone()).is_none());
+
+                    let transport = SessionTransport {
+                        expires: Some(UTC::now() - Duration::seconds(1)),
+                        session: session,
+                    };
+
+                    let renewed = manager.renew(&transport);
+                    assert!(renewed.expires.unwrap() > UTC::now());
+                    assert_eq!(renewed.session.get_bytes(&key), Some(&value));
+                }
             }
         }
     }
 
     test_cases!(ChaCha20Poly1305SessionManager, chacha20poly1305);
+    test_cases!(AesGcmSessionManager, aes_gcm);
+    test_cases!(SigningSessionManager, signing);
+    test_cases!(XChaCha20Poly1305SessionManager, xchacha20poly1305);
+
+    #[test]
+    fn hchacha20_matches_draft_irtf_cfrg_xchacha_test_vector() {
+        use super::hchacha20;
+
+        // draft-irtf-cfrg-xchacha-03 Appendix A.1.
+        let mut key = [0u8; 32];
+        for i in 0..32 {
+            key[i] = i as u8;
+        }
+        let nonce = [0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x4a, 0x00, 0x00, 0x00, 0x00, 0x31,
+                     0x41, 0x59, 0x27];
+        let expected = [0x82, 0x41, 0x3b, 0x42, 0x27, 0xb2, 0x7b, 0xfe, 0xd3, 0x0e, 0x42, 0x50,
+                         0x8a, 0x87, 0x7d, 0x73, 0xa0, 0xf9, 0xe4, 0xd5, 0x8a, 0x74, 0xa8, 0x53,
+                         0xc1, 0x2e, 0xc4, 0x13, 0x26, 0xd3, 0xec, 0xdc];
+
+        assert_eq!(hchacha20(&key, &nonce), expected);
+    }
+
+    #[test]
+    fn signing_key_is_independent_of_aead_key_for_same_password() {
+        use crypto::scrypt::{scrypt, ScryptParams};
+
+        use super::{SCRYPT_SALT, SIGNING_SCRYPT_SALT};
+
+        let password = b"hunter2";
+        let params = ScryptParams::new(1, 8, 1);
+
+        let mut aead_key = [0; 32];
+        scrypt(password, SCRYPT_SALT, &params, &mut aead_key);
+
+        let mut mac_key = [0; 32];
+        scrypt(password, SIGNING_SCRYPT_SALT, &params, &mut mac_key);
+
+        assert_ne!(aead_key, mac_key);
+    }
 }
@@ -0,0 +1,330 @@
+//! Server-side session storage, keyed by an opaque session id.
+//!
+//! Instead of carrying the full `Session` in the cookie, callers using a `SessionStore` hand the
+//! client only a random session id; the `Session` itself lives server-side. This keeps large or
+//! sensitive session data off the client and allows the server to invalidate a session it no
+//! longer trusts. An AEAD `SessionManager` can still be layered on top to encrypt the stored
+//! blobs at rest (e.g. before writing them out in a `FileStore`).
+
+use base64;
+use bincode::{self, Infinite};
+use chrono::{DateTime, UTC};
+use ring::rand::SystemRandom;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use error::SessionError;
+use session::{expired, Session};
+
+/// Trait for server-side session storage keyed by an opaque session id.
+///
+/// Every method takes `&self`, not `&mut self`: implementors are expected to manage their own
+/// concurrency internally (typically behind a `Mutex`), so a single `Arc<dyn SessionStore<V>>`
+/// can be shared across worker threads without an outer lock.
+pub trait SessionStore<V = HashMap<String, Vec<u8>>>: Send + Sync {
+    /// Load the session stored under `id`, or `None` if there isn't one or it has expired.
+    /// Mirrors `SessionManager::deserialize`'s expiry check: an id whose `expires` has passed is
+    /// treated the same as one that was never stored.
+    fn load(&self, id: &str) -> Result<Option<Session<V>>, SessionError>;
+
+    /// Store `session` under `id`, replacing any session already stored there. `expires` is the
+    /// UTC instant after which `clear_expired` may remove it.
+    fn store(&self,
+             id: &str,
+             expires: Option<DateTime<UTC>>,
+             session: Session<V>)
+             -> Result<(), SessionError>;
+
+    /// Remove the session stored under `id`, if any.
+    fn destroy(&self, id: &str) -> Result<(), SessionError>;
+
+    /// Remove every stored session whose `expires` is in the past.
+    fn clear_expired(&self) -> Result<(), SessionError>;
+
+    /// Generate a fresh, random 128-bit session id, base64url-encoded for use as a cookie value.
+    fn generate_id(&self) -> Result<String, SessionError> {
+        let rng = SystemRandom::new();
+        let mut bytes = [0; 16];
+        rng.fill(&mut bytes)
+            .map_err(|_| {
+                warn!("Failed to get random bytes");
+                SessionError::InternalError
+            })?;
+        Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Move the session currently stored under `old_id` to a freshly generated id, destroying
+    /// `old_id`, and return the new id (or `None` if there was no session stored under
+    /// `old_id`).
+    ///
+    /// This is the standard defense against session fixation: call it whenever a session's
+    /// privilege level changes (e.g. on login) so the identifier an attacker fixated beforehand
+    /// stops referring to any session, while the legitimate session data is carried over under
+    /// the new id.
+    fn renew(&self,
+             old_id: &str,
+             expires: Option<DateTime<UTC>>)
+             -> Result<Option<String>, SessionError>
+        where V: Clone
+    {
+        let session = match self.load(old_id)? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+
+        let new_id = self.generate_id()?;
+        self.store(&new_id, expires, session)?;
+        self.destroy(old_id)?;
+
+        Ok(Some(new_id))
+    }
+}
+
+/// In-memory `SessionStore`. Sessions do not survive a process restart.
+pub struct MemoryStore<V = HashMap<String, Vec<u8>>> {
+    sessions: Mutex<HashMap<String, (Option<DateTime<UTC>>, Session<V>)>>,
+}
+
+impl<V> MemoryStore<V> {
+    /// Create an empty in-memory store.
+    pub fn new() -> Self {
+        MemoryStore { sessions: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<V> SessionStore<V> for MemoryStore<V>
+    where V: Clone + Send + Sync
+{
+    fn load(&self, id: &str) -> Result<Option<Session<V>>, SessionError> {
+        let mut sessions = self.sessions.lock().map_err(|_| SessionError::InternalError)?;
+
+        match sessions.get(id) {
+            Some(&(expires, _)) if expired(expires) => {}
+            Some(&(_, ref session)) => return Ok(Some(session.clone())),
+            None => return Ok(None),
+        }
+
+        sessions.remove(id);
+        Ok(None)
+    }
+
+    fn store(&self,
+             id: &str,
+             expires: Option<DateTime<UTC>>,
+             session: Session<V>)
+             -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().map_err(|_| SessionError::InternalError)?;
+        sessions.insert(id.to_string(), (expires, session));
+        Ok(())
+    }
+
+    fn destroy(&self, id: &str) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().map_err(|_| SessionError::InternalError)?;
+        sessions.remove(id);
+        Ok(())
+    }
+
+    fn clear_expired(&self) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().map_err(|_| SessionError::InternalError)?;
+        let now = UTC::now();
+        sessions.retain(|_, &mut (expires, _)| expires.map(|expires| expires >= now).unwrap_or(true));
+        Ok(())
+    }
+}
+
+/// File-backed `SessionStore`.
+///
+/// Every session currently on disk is loaded into memory when the store is constructed, and the
+/// whole table is flushed back out after every mutating call.
+pub struct FileStore<V = HashMap<String, Vec<u8>>> {
+    path: PathBuf,
+    sessions: Mutex<HashMap<String, (Option<DateTime<UTC>>, Session<V>)>>,
+}
+
+impl<V> FileStore<V>
+    where V: Serialize + DeserializeOwned
+{
+    /// Open (or create) a file-backed store at `path`, loading any sessions already persisted
+    /// there.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<Self, SessionError> {
+        let path = path.into();
+
+        let sessions = if path.exists() {
+            let mut file = File::open(&path).map_err(|_| SessionError::InternalError)?;
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).map_err(|_| SessionError::InternalError)?;
+
+            if bytes.is_empty() {
+                HashMap::new()
+            } else {
+                bincode::deserialize(&bytes).map_err(|_| SessionError::InternalError)?
+            }
+        } else {
+            HashMap::new()
+        };
+
+        Ok(FileStore {
+            path: path,
+            sessions: Mutex::new(sessions),
+        })
+    }
+
+    fn flush(&self,
+              sessions: &HashMap<String, (Option<DateTime<UTC>>, Session<V>)>)
+              -> Result<(), SessionError> {
+        let bytes = bincode::serialize(sessions, Infinite).map_err(|_| SessionError::InternalError)?;
+        let mut file = File::create(&self.path).map_err(|_| SessionError::InternalError)?;
+        file.write_all(&bytes).map_err(|_| SessionError::InternalError)
+    }
+}
+
+impl<V> SessionStore<V> for FileStore<V>
+    where V: Clone + Serialize + DeserializeOwned + Send + Sync
+{
+    fn load(&self, id: &str) -> Result<Option<Session<V>>, SessionError> {
+        let mut sessions = self.sessions.lock().map_err(|_| SessionError::InternalError)?;
+
+        match sessions.get(id) {
+            Some(&(expires, _)) if expired(expires) => {}
+            Some(&(_, ref session)) => return Ok(Some(session.clone())),
+            None => return Ok(None),
+        }
+
+        sessions.remove(id);
+        self.flush(&sessions)?;
+        Ok(None)
+    }
+
+    fn store(&self,
+             id: &str,
+             expires: Option<DateTime<UTC>>,
+             session: Session<V>)
+             -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().map_err(|_| SessionError::InternalError)?;
+        sessions.insert(id.to_string(), (expires, session));
+        self.flush(&sessions)
+    }
+
+    fn destroy(&self, id: &str) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().map_err(|_| SessionError::InternalError)?;
+        sessions.remove(id);
+        self.flush(&sessions)
+    }
+
+    fn clear_expired(&self) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().map_err(|_| SessionError::InternalError)?;
+        let now = UTC::now();
+        sessions.retain(|_, &mut (expires, _)| expires.map(|expires| expires >= now).unwrap_or(true));
+        self.flush(&sessions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, UTC};
+    use std::fs;
+    use std::path::PathBuf;
+
+    use session::Session;
+    use super::{FileStore, MemoryStore, SessionStore};
+
+    #[test]
+    fn memory_store_round_trip() {
+        let store = MemoryStore::new();
+
+        let key = "foo".to_string();
+        let value = b"bar".to_vec();
+        let mut session = Session::new();
+        session.insert_bytes(&key, value.clone());
+
+        let id = store.generate_id().expect("couldn't generate id");
+        assert_eq!(store.load(&id).expect("couldn't load"), None);
+
+        store.store(&id, None, session.clone()).expect("couldn't store");
+        let loaded = store.load(&id).expect("couldn't load").expect("session missing");
+        assert_eq!(loaded.get_bytes(&key), Some(&value));
+
+        store.destroy(&id).expect("couldn't destroy");
+        assert_eq!(store.load(&id).expect("couldn't load"), None);
+    }
+
+    #[test]
+    fn memory_store_load_rejects_expired_without_clear_expired() {
+        let store = MemoryStore::new();
+
+        let id = store.generate_id().expect("couldn't generate id");
+        store.store(&id, Some(UTC::now() - Duration::seconds(1)), Session::new())
+            .expect("couldn't store");
+
+        // No call to `clear_expired` here: `load` itself must treat an expired id as absent.
+        assert_eq!(store.load(&id).expect("couldn't load"), None);
+    }
+
+    #[test]
+    fn memory_store_renew_rotates_id_and_preserves_session() {
+        let store = MemoryStore::new();
+
+        let key = "foo".to_string();
+        let value = b"bar".to_vec();
+        let mut session = Session::new();
+        session.insert_bytes(&key, value.clone());
+
+        let old_id = store.generate_id().expect("couldn't generate id");
+        store.store(&old_id, None, session).expect("couldn't store");
+
+        let new_id = store.renew(&old_id, None).expect("couldn't renew").expect("session missing");
+        assert_ne!(old_id, new_id);
+
+        assert_eq!(store.load(&old_id).expect("couldn't load"), None);
+        let loaded = store.load(&new_id).expect("couldn't load").expect("session missing");
+        assert_eq!(loaded.get_bytes(&key), Some(&value));
+    }
+
+    #[test]
+    fn memory_store_clear_expired() {
+        let store = MemoryStore::new();
+
+        let expired_id = store.generate_id().expect("couldn't generate id");
+        store.store(&expired_id, Some(UTC::now() - Duration::seconds(1)), Session::new())
+            .expect("couldn't store");
+
+        let live_id = store.generate_id().expect("couldn't generate id");
+        store.store(&live_id, Some(UTC::now() + Duration::days(1)), Session::new())
+            .expect("couldn't store");
+
+        store.clear_expired().expect("couldn't clear expired");
+
+        assert_eq!(store.load(&expired_id).expect("couldn't load"), None);
+        assert!(store.load(&live_id).expect("couldn't load").is_some());
+    }
+
+    #[test]
+    fn file_store_persists_across_instances() {
+        let mut path: PathBuf = ::std::env::temp_dir();
+        path.push(format!("rust-secure-session-test-{}.bin", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let key = "foo".to_string();
+        let value = b"bar".to_vec();
+        let mut session = Session::new();
+        session.insert_bytes(&key, value.clone());
+
+        let id = {
+            let store: FileStore = FileStore::new(path.clone()).expect("couldn't open store");
+            let id = store.generate_id().expect("couldn't generate id");
+            store.store(&id, None, session).expect("couldn't store");
+            id
+        };
+
+        let store: FileStore = FileStore::new(path.clone()).expect("couldn't reopen store");
+        let loaded = store.load(&id).expect("couldn't load").expect("session missing");
+        assert_eq!(loaded.get_bytes(&key), Some(&value));
+
+        let _ = fs::remove_file(&path);
+    }
+}